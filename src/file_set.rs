@@ -1,21 +1,169 @@
 use std::fs;
 use std::io;
+use std::io::{Read, Seek, SeekFrom};
 use std::mem::swap;
+use std::path::Path;
+use std::thread;
+use std::time::SystemTime;
 
 use std::collections::BTreeMap;
+use crc::crc32;
 use super::index::*;
 use super::segment::*;
-use super::LogOptions;
+use super::{Compression, LogOptions, RecordVersion};
+
+/// Width of the bare length prefix used by the legacy (pre-CRC) framing.
+const RECORD_LEN_PREFIX: u64 = 4;
+
+/// Version tag written as the first header byte of a [`RecordVersion::Crc`] record.
+const RECORD_VERSION_CRC_TAG: u8 = 1;
+
+/// Byte width of a record's framing header for the given version: a bare `u32`
+/// length prefix for the legacy format, or a one-byte version tag plus a `u32`
+/// length and a `u32` payload CRC for the checksummed format.
+fn record_header_len(version: RecordVersion) -> u64 {
+    match version {
+        RecordVersion::Legacy => RECORD_LEN_PREFIX,
+        RecordVersion::Crc => 1 + 4 + 4,
+    }
+}
+
+/// Decode the record that begins at `position` in `reader`, validating its
+/// framing (and, for [`RecordVersion::Crc`], the payload CRC) against the
+/// `len`-byte file. Returns the byte position just past the record, or `None`
+/// when the record is torn, truncated, or fails its checksum — the boundary a
+/// crash or bit-rot leaves behind.
+fn decode_record<R: Read + Seek>(reader: &mut R,
+                                 position: u64,
+                                 len: u64,
+                                 version: RecordVersion)
+                                 -> io::Result<Option<u64>> {
+    let header_len = record_header_len(version);
+    if position + header_len > len {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(position))?;
+
+    let (payload_len, crc) = match version {
+        RecordVersion::Legacy => {
+            let mut prefix = [0u8; 4];
+            if reader.read_exact(&mut prefix).is_err() {
+                return Ok(None);
+            }
+            (u32::from_le_bytes(prefix) as u64, None)
+        }
+        RecordVersion::Crc => {
+            let mut buf = [0u8; 9];
+            if reader.read_exact(&mut buf).is_err() {
+                return Ok(None);
+            }
+            // a garbage version byte means the header is corrupt even if the
+            // length and CRC happen to line up: treat it as a torn record.
+            if buf[0] != RECORD_VERSION_CRC_TAG {
+                return Ok(None);
+            }
+            let payload_len = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]) as u64;
+            let crc = u32::from_le_bytes([buf[5], buf[6], buf[7], buf[8]]);
+            (payload_len, Some(crc))
+        }
+    };
+
+    let record_end = position + header_len + payload_len;
+    if record_end > len {
+        return Ok(None);
+    }
+
+    if let Some(expected) = crc {
+        let mut payload = vec![0u8; payload_len as usize];
+        if reader.read_exact(&mut payload).is_err() {
+            return Ok(None);
+        }
+        if crc32::checksum_ieee(&payload) != expected {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(record_end))
+}
+
+/// Decode and validate the record that begins at `position` in the segment
+/// file at `path`, returning the byte position just past the whole record.
+fn record_end(path: &Path, position: u64, version: RecordVersion) -> io::Result<u64> {
+    let file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = io::BufReader::new(file);
+    decode_record(&mut reader, position, len, version)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unreadable record header"))
+}
+
+/// Scan the segment file at `path` forward from the byte position `position`,
+/// decoding each record and appending the recovered `(offset, file_position)`
+/// pair into `index`, starting at logical offset `offset`. Scanning stops at
+/// the first record that does not fully fit or fails validation, which is the
+/// boundary a crash would have left behind.
+///
+/// Returns the next offset past the last fully decoded record together with the
+/// byte position just past it — the boundary a torn tail should be truncated
+/// to, so the caller needs only this single pass over the segment.
+fn reindex_segment(index: &mut Index,
+                   path: &Path,
+                   mut offset: u64,
+                   mut position: u64,
+                   version: RecordVersion)
+                   -> io::Result<(u64, u64)> {
+    let file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = io::BufReader::new(file);
+
+    while let Some(record_end) = decode_record(&mut reader, position, len, version)? {
+        index.append(offset, position as u32)?;
+        offset += 1;
+        position = record_end;
+    }
+
+    Ok((offset, position))
+}
+
+/// Copy the first `valid_bytes` of the file at `src` into a freshly created
+/// file of that exact size under `dest_dir`, keeping the original file name.
+/// Segments and indexes are preallocated to their configured capacity, so only
+/// the written prefix is copied — the trailing zero padding is left out of the
+/// snapshot.
+fn copy_valid_range(src: &Path, valid_bytes: u64, dest_dir: &Path) -> io::Result<()> {
+    copy_range_to_capacity(src, valid_bytes, valid_bytes, dest_dir)
+}
+
+/// Copy the first `valid_bytes` of the file at `src` into a file under
+/// `dest_dir`, then grow the destination to `capacity` bytes. Closed files are
+/// copied at their valid size (`capacity == valid_bytes`), but the active index
+/// is mmap-backed at its configured capacity, so it must be restored to that
+/// full size for the snapshot to accept further appends without a forced roll.
+fn copy_range_to_capacity(src: &Path, valid_bytes: u64, capacity: u64, dest_dir: &Path) -> io::Result<()> {
+    let name = src.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "source file has no name"))?;
+    let mut reader = fs::File::open(src)?.take(valid_bytes);
+    let mut out = fs::File::create(dest_dir.join(name))?;
+    io::copy(&mut reader, &mut out)?;
+    out.set_len(capacity)?;
+    out.sync_all()?;
+    Ok(())
+}
 
 pub struct FileSet {
     active: (Index, Segment),
     closed: BTreeMap<u64, (Index, Segment)>,
     opts: LogOptions,
+    recovered_next_offset: u64,
+    recovered_discarded: u64,
+    // background segment compressions keyed by the segment's starting offset;
+    // each handle yields the reopened compressed segment to swap into `closed`.
+    compressing: BTreeMap<u64, thread::JoinHandle<io::Result<Segment>>>,
 }
 
 impl FileSet {
     pub fn load_log(opts: LogOptions) -> io::Result<FileSet> {
         let mut segments = BTreeMap::new();
+        let mut segment_paths = BTreeMap::new();
         let mut indexes = BTreeMap::new();
 
         let files = fs::read_dir(&opts.log_dir)?
@@ -36,6 +184,7 @@ impl FileSet {
                     };
 
                     let offset = segment.starting_offset();
+                    segment_paths.insert(offset, f.path());
                     segments.insert(offset, segment);
                 }
                 Some(ext) if INDEX_FILE_NAME_EXTENSION.eq(ext) => {
@@ -55,23 +204,49 @@ impl FileSet {
             }
         }
 
-        // pair up the index and segments (there should be an index per segment)
-        let mut closed = segments.into_iter()
-            .map(move |(i, s)| {
-                match indexes.remove(&i) {
-                    Some(v) => (i, (v, s)),
-                    None => {
-                        // TODO: create the index from the segment
-                        panic!("No index found for segment starting at {}", i);
+        // the highest-offset segment becomes the active (reusable) one; it is the
+        // only segment that may have been mid-append during a crash, so its index
+        // is rebuilt from scratch below in a single scan rather than here.
+        let last_entry = segments.keys().next_back().cloned();
+
+        // pair up the index and segments (there should be an index per segment).
+        // a missing index is rebuilt from scratch by scanning the segment, and an
+        // index that trails its segment (crash before the index write landed) has
+        // its tail entries recovered the same way.
+        let mut closed = BTreeMap::new();
+        for (i, s) in segments.into_iter() {
+            let path = segment_paths.get(&i).cloned().expect("segment path recorded on open");
+            let version = s.framing_version();
+            let index = match indexes.remove(&i) {
+                Some(mut index) if Some(i) != last_entry => {
+                    // recover any entries the segment holds past the last indexed
+                    // offset (crash before write to index)
+                    let next_offset = index.next_offset();
+                    let resume_pos = match index.last_entry() {
+                        Some((_, position)) => record_end(&path, position as u64, version)?,
+                        None => Header::LEN as u64,
+                    };
+                    reindex_segment(&mut index, &path, next_offset, resume_pos, version)?;
+                    index
+                }
+                // active segment: defer recovery to the single-pass rebuild below
+                Some(index) => index,
+                None => {
+                    info!("Rebuilding missing index for segment starting at {}", i);
+                    let mut index = Index::new(&opts.log_dir, i, opts.index_max_bytes)?;
+                    // the active segment is scanned once, by the rebuild below
+                    if Some(i) != last_entry {
+                        reindex_segment(&mut index, &path, i, Header::LEN as u64, version)?;
                     }
+                    index
                 }
-            })
-            .collect::<BTreeMap<u64, (Index, Segment)>>();
+            };
+            closed.insert(i, (index, s));
+        }
 
         // try to reuse the last index if it is not full. otherwise, open a new index
         // at the correct offset
-        let last_entry = closed.keys().next_back().cloned();
-        let (ind, seg) = match last_entry {
+        let (mut ind, mut seg) = match last_entry {
             Some(off) => {
                 info!("Reusing index and segment starting at offset {}", off);
                 closed.remove(&off).unwrap()
@@ -79,11 +254,38 @@ impl FileSet {
             None => {
                 info!("Starting new index and segment at offset 0");
                 let ind = Index::new(&opts.log_dir, 0, opts.index_max_bytes)?;
-                let seg = Segment::new(&opts.log_dir, 0, opts.log_max_bytes)?;
+                let seg = Segment::new(&opts.log_dir, 0, opts.log_max_bytes, opts.record_version)?;
                 (ind, seg)
             }
         };
 
+        // rebuild the active index in a single forward scan of the segment,
+        // recovering every fully valid record and stopping at a torn tail. This
+        // both repairs an index that trailed its segment and drops any entry that
+        // was written for a record whose payload never made it to disk.
+        let (recovered_next_offset, recovered_discarded) = match last_entry {
+            Some(off) => {
+                let path = segment_paths.get(&off).cloned().expect("segment path recorded on open");
+                let version = seg.framing_version();
+                let start = seg.starting_offset();
+                let prior_offset = ind.next_offset();
+
+                ind.truncate(start)?;
+                let (next_offset, valid_bytes) =
+                    reindex_segment(&mut ind, &path, start, Header::LEN as u64, version)?;
+
+                // a torn tail shows up as segment bytes past the last valid record
+                if valid_bytes < seg.size() as u64 {
+                    warn!("Truncating torn tail of active segment {}: discarding {} bytes past offset {}",
+                          off, seg.size() as u64 - valid_bytes, next_offset);
+                    seg.truncate(valid_bytes)?;
+                }
+
+                (next_offset, prior_offset.saturating_sub(next_offset))
+            }
+            None => (0, 0),
+        };
+
         // mark all closed indexes as readonly (indexes are not opened as readonly)
         for &mut (ref mut ind, _) in closed.values_mut() {
             ind.set_readonly()?;
@@ -93,9 +295,28 @@ impl FileSet {
             active: (ind, seg),
             closed: closed,
             opts: opts,
+            recovered_next_offset: recovered_next_offset,
+            recovered_discarded: recovered_discarded,
+            compressing: BTreeMap::new(),
         })
     }
 
+    /// The offset the active segment was recovered to on load: one past its
+    /// last fully valid record. The active index's `next_offset` equals it right
+    /// after load. It is lower than the offset the index held before recovery
+    /// when records the index had already covered were discarded as a torn tail.
+    pub fn recovered_next_offset(&self) -> u64 {
+        self.recovered_next_offset
+    }
+
+    /// The number of uncommitted records discarded from the active segment's
+    /// tail during load — the difference between the offset the index claimed
+    /// before recovery and the offset the segment could actually be recovered
+    /// to. Zero after a clean shutdown.
+    pub fn recovered_discarded(&self) -> u64 {
+        self.recovered_discarded
+    }
+
     pub fn active_segment_mut(&mut self) -> &mut Segment {
         &mut self.active.1
     }
@@ -108,6 +329,14 @@ impl FileSet {
         &self.active.0
     }
 
+    /// Find the index+segment pair covering `offset`. A closed segment may have
+    /// been compressed by [`roll_segment`]; the codec recorded in its header
+    /// lets the `Segment` decompress transparently on read, and its paired index
+    /// still stores positions into the uncompressed byte stream, so callers seek
+    /// exactly as they would for an uncompressed segment. While a background
+    /// compression is still in flight the segment is served uncompressed; the
+    /// compressed replacement is swapped in once reaped, so reads never block on
+    /// or observe an in-flight compression.
     pub fn find(&self, offset: u64) -> Option<&(Index, Segment)> {
         let active_seg_start_off = self.active.0.starting_offset();
         if offset >= active_seg_start_off {
@@ -120,6 +349,9 @@ impl FileSet {
     }
 
     pub fn roll_segment(&mut self) -> io::Result<()> {
+        // fold in any background compressions that finished since the last roll
+        self.reap_compression();
+
         self.active.0.set_readonly()?;
         self.active.1.flush_sync()?;
 
@@ -129,16 +361,282 @@ impl FileSet {
 
         // set the segment and index to the new active index/seg
         let mut p = {
-            let seg = Segment::new(&self.opts.log_dir, next_offset, self.opts.log_max_bytes)?;
+            let seg = Segment::new(&self.opts.log_dir, next_offset, self.opts.log_max_bytes, self.opts.record_version)?;
             let ind = Index::new(&self.opts.log_dir, next_offset, self.opts.index_max_bytes)?;
             (ind, seg)
         };
         swap(&mut p, &mut self.active);
-        self.closed.insert(p.1.starting_offset(), p);
+
+        // move the just-sealed pair into `closed` before anything fallible so a
+        // failure below can never orphan it out of the in-memory set.
+        let start = p.1.starting_offset();
+        self.closed.insert(start, p);
+
+        // the sealed segment is now immutable, so compress it to reclaim disk if
+        // a codec is configured. The codec is stamped into the segment header,
+        // leaving the paired index (which points into the uncompressed byte
+        // stream) unchanged; the active segment stays uncompressed for append
+        // performance. Compression runs on a background worker so a deflate/LZ4
+        // pass over a full segment never blocks this roll or the next append;
+        // reads keep using the uncompressed segment until the worker's result is
+        // reaped and swapped in. A failure is non-fatal — the uncompressed
+        // segment remains fully readable.
+        if self.opts.compression != Compression::None {
+            let path = self.closed[&start].1.path().to_owned();
+            let codec = self.opts.compression;
+            let max_bytes = self.opts.log_max_bytes;
+            let handle = thread::spawn(move || Segment::compress_file(&path, codec, max_bytes));
+            self.compressing.insert(start, handle);
+        }
+        Ok(())
+    }
+
+    /// Swap in any background segment compressions that have finished, without
+    /// blocking on those still running. A compression that failed leaves the
+    /// uncompressed segment in place.
+    pub fn reap_compression(&mut self) {
+        let done: Vec<u64> = self.compressing
+            .iter()
+            .filter(|&(_, handle)| handle.is_finished())
+            .map(|(&off, _)| off)
+            .collect();
+        for off in done {
+            self.collect_compression(off);
+        }
+    }
+
+    /// Block until every in-flight compression has finished and been swapped in.
+    /// Used on paths that must observe a stable set of closed segments, e.g.
+    /// retention and snapshotting.
+    fn drain_compression(&mut self) {
+        let offsets: Vec<u64> = self.compressing.keys().cloned().collect();
+        for off in offsets {
+            self.collect_compression(off);
+        }
+    }
+
+    /// Join the worker for `off` and replace the in-memory segment with the
+    /// compressed one it produced, keeping the uncompressed segment on failure.
+    fn collect_compression(&mut self, off: u64) {
+        let handle = match self.compressing.remove(&off) {
+            Some(handle) => handle,
+            None => return,
+        };
+        match handle.join() {
+            Ok(Ok(seg)) => {
+                if let Some(&mut (_, ref mut existing)) = self.closed.get_mut(&off) {
+                    *existing = seg;
+                }
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to compress closed segment {}: {}; keeping it uncompressed", off, e);
+            }
+            Err(_) => {
+                error!("Background compression of segment {} panicked; keeping it uncompressed", off);
+            }
+        }
+    }
+
+    /// Copy every closed and active segment+index pair into `dest` to produce a
+    /// standalone snapshot that [`FileSet::load_log`] can open directly, without
+    /// stopping writers. The active segment is flushed first so its on-disk
+    /// bytes are consistent with the active index, and each file is copied by
+    /// its valid byte range rather than its preallocated capacity. The copy of
+    /// the active index is then grown back to `index_max_bytes`.
+    pub fn save_to_path<P: AsRef<Path>>(&mut self, dest: P) -> io::Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+
+        // settle any in-flight compression so the snapshot copies the final form
+        // of every closed segment
+        self.drain_compression();
+
+        for &(ref ind, ref seg) in self.closed.values() {
+            copy_valid_range(seg.path(), seg.size() as u64, dest)?;
+            copy_valid_range(ind.path(), ind.size() as u64, dest)?;
+        }
+
+        // flush both active files so the snapshot is internally consistent: the
+        // segment's durable bytes match the index's most recent entries
+        self.active.1.flush_sync()?;
+        self.active.0.flush()?;
+        copy_valid_range(self.active.1.path(), self.active.1.size() as u64, dest)?;
+        // the active index must keep its full capacity so the restored log can
+        // accept appends without immediately rolling
+        copy_range_to_capacity(self.active.0.path(),
+                               self.active.0.size() as u64,
+                               self.opts.index_max_bytes as u64,
+                               dest)?;
+
         Ok(())
     }
 
+    /// Delete whole closed segment+index pairs, oldest first, until the
+    /// configured [`RetentionPolicy`] is satisfied. A pair is removed once the
+    /// total closed size exceeds `max_bytes`, the segment is older than
+    /// `max_age`, or all of its records fall below `min_offset` — but never the
+    /// active pair, and never a segment any of whose records are at or above
+    /// `min_offset`. Returns the new lowest readable offset so readers can
+    /// detect that earlier data is gone.
+    pub fn enforce_retention(&mut self) -> io::Result<u64> {
+        // settle in-flight compression so sizes and files are stable before we
+        // measure against the policy and delete from disk
+        self.drain_compression();
+
+        let max_bytes = self.opts.retention.max_bytes;
+        let max_age = self.opts.retention.max_age;
+        let keep_from = self.opts.retention.min_offset;
+        let active_start = self.active.0.starting_offset();
+
+        let mut total_bytes: u64 = self.closed
+            .values()
+            .map(|&(ref ind, ref seg)| seg.size() as u64 + ind.size() as u64)
+            .sum();
+
+        loop {
+            let off = match self.closed.keys().next().cloned() {
+                Some(off) => off,
+                None => break,
+            };
+            // boundary offset of the next pair (or the active segment): every
+            // record in this segment is below it
+            let next_start = self.closed
+                .range((off + 1)..)
+                .next()
+                .map(|(&k, _)| k)
+                .unwrap_or(active_start);
+
+            // a segment straddling the keep-from floor must be retained whole
+            if keep_from.map_or(false, |keep| next_start > keep) {
+                break;
+            }
+
+            let (seg_bytes, seg_path, ind_path) = {
+                let &(ref ind, ref seg) = &self.closed[&off];
+                (seg.size() as u64 + ind.size() as u64, seg.path().to_owned(), ind.path().to_owned())
+            };
+
+            let below_floor = keep_from.map_or(false, |keep| next_start <= keep);
+            let over_size = max_bytes.map_or(false, |m| total_bytes > m);
+            let too_old = match max_age {
+                Some(age) => {
+                    let modified = fs::metadata(&seg_path)?.modified()?;
+                    SystemTime::now().duration_since(modified).map(|d| d > age).unwrap_or(false)
+                }
+                None => false,
+            };
+
+            if !(below_floor || over_size || too_old) {
+                break;
+            }
+
+            info!("Pruning closed segment starting at offset {}", off);
+            fs::remove_file(&seg_path)?;
+            fs::remove_file(&ind_path)?;
+            self.closed.remove(&off);
+            total_bytes -= seg_bytes;
+        }
+
+        Ok(self.closed.keys().next().cloned().unwrap_or(active_start))
+    }
+
     pub fn log_options(&self) -> &LogOptions {
         &self.opts
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn crc_record(payload: &[u8]) -> Vec<u8> {
+        let mut v = Vec::with_capacity(9 + payload.len());
+        v.push(RECORD_VERSION_CRC_TAG);
+        v.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        v.extend_from_slice(&crc32::checksum_ieee(payload).to_le_bytes());
+        v.extend_from_slice(payload);
+        v
+    }
+
+    fn legacy_record(payload: &[u8]) -> Vec<u8> {
+        let mut v = Vec::with_capacity(4 + payload.len());
+        v.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        v.extend_from_slice(payload);
+        v
+    }
+
+    #[test]
+    fn decode_crc_record_round_trips() {
+        let bytes = crc_record(b"hello");
+        let len = bytes.len() as u64;
+        let mut cur = Cursor::new(bytes);
+        assert_eq!(decode_record(&mut cur, 0, len, RecordVersion::Crc).unwrap(), Some(len));
+    }
+
+    #[test]
+    fn decode_rejects_corrupt_crc() {
+        let mut bytes = crc_record(b"hello");
+        // flip a payload byte so the stored CRC no longer matches
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let len = bytes.len() as u64;
+        let mut cur = Cursor::new(bytes);
+        assert_eq!(decode_record(&mut cur, 0, len, RecordVersion::Crc).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_rejects_bad_version_tag() {
+        let mut bytes = crc_record(b"hello");
+        bytes[0] = 0xff;
+        let len = bytes.len() as u64;
+        let mut cur = Cursor::new(bytes);
+        assert_eq!(decode_record(&mut cur, 0, len, RecordVersion::Crc).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_stops_on_torn_tail() {
+        let mut bytes = crc_record(b"hello world");
+        bytes.truncate(bytes.len() - 3); // payload runs past end of file
+        let len = bytes.len() as u64;
+        let mut cur = Cursor::new(bytes);
+        assert_eq!(decode_record(&mut cur, 0, len, RecordVersion::Crc).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_legacy_record_round_trips() {
+        let bytes = legacy_record(b"payload");
+        let len = bytes.len() as u64;
+        let mut cur = Cursor::new(bytes);
+        assert_eq!(decode_record(&mut cur, 0, len, RecordVersion::Legacy).unwrap(), Some(len));
+    }
+
+    #[test]
+    fn forward_scan_stops_before_torn_tail() {
+        // two intact records followed by a torn third, as a crash mid-append
+        // would leave the active segment
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&crc_record(b"one"));
+        bytes.extend_from_slice(&crc_record(b"two"));
+        let valid_end = bytes.len() as u64;
+        let mut torn = crc_record(b"three");
+        torn.truncate(torn.len() - 2);
+        bytes.extend_from_slice(&torn);
+
+        let total = bytes.len() as u64;
+        let mut cur = Cursor::new(bytes);
+
+        // walk the records the way reindex_segment does and confirm the scan
+        // stops at the last intact record, leaving the torn tail for truncation
+        let mut position = 0u64;
+        let mut count = 0u64;
+        while let Some(record_end) = decode_record(&mut cur, position, total, RecordVersion::Crc).unwrap() {
+            position = record_end;
+            count += 1;
+        }
+
+        assert_eq!(count, 2);
+        assert_eq!(position, valid_end);
+        assert!(position < total);
+    }
+}